@@ -1,15 +1,34 @@
 extern crate termion;
-
+extern crate unicode_segmentation;
+extern crate unicode_width;
+extern crate regex;
+
+mod rope;
+mod undo;
+
+use rope::Rope;
+use undo::{Edit, History};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+use regex::Regex;
 use termion::{color, clear, cursor, style};
-use termion::event::Key;
-use termion::input::TermRead;
+use termion::event::{Event, Key, MouseButton, MouseEvent};
+use termion::input::{MouseTerminal, TermRead};
 use termion::raw::IntoRawMode;
+use termion::screen::{AlternateScreen, ToMainScreen};
 use std::cmp::{max, min};
 use std::env;
+use std::mem;
+use std::panic;
+use std::fs;
 use std::fs::File;
 use std::path::Path;
 use std::io;
-use std::io::{BufRead, BufReader, Write, stdin, stdout};
+use std::io::{Read, Write, stdout};
+use std::fmt::Write as _;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 type Point = usize;
 
@@ -31,83 +50,254 @@ impl View {
 }
 
 
-// Ideally, this should be a Rope. Let's make the API the same, so it can later be replaced by a Rope
-// implementation.
+// Chunk size for the background file loader. 16 MiB keeps the number of
+// channel messages small for huge files while still letting the first
+// chunk (and first screen) show up quickly.
+const LOAD_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+// Backed by a Rope (see rope.rs), so insert/delete and line lookup stay
+// fast even on big files. `length` and `newlines` are kept as plain fields,
+// updated incrementally on every edit, so the common case of "how big is
+// this buffer" doesn't have to walk the rope.
+//
+// `newlines` counts '\n' bytes, not lines: a file without a trailing
+// newline has one more line than it has newline characters. Use
+// `num_lines` when you want the line count.
 struct Text {
     length: usize,
     newlines: usize,
-    text: Vec<String>
+    rope: Rope
 }
 
 impl Text {
-    fn from_file<P: AsRef<Path>>(file_path: P) -> io::Result<Text> {
-        let f = try!(File::open(&file_path));
-        let reader = BufReader::new(f);
-        let mut text = Vec::new();
-        let mut length = 0;
-        let mut newlines = 0;
-        for l in reader.lines() {
-            let line = l.unwrap();
-            length += line.len() + 1; // Count the newline. TODO: This won't work if the newline is not '\n'.
-            newlines += 1;
-            text.push(line);
-        }
-        Ok(Text{
-            length: if length == 0 {0} else {length - 1}, // An extra line was added above.
-            newlines: newlines,
-            text: text
-        })
+    fn empty() -> Text {
+        Text { length: 0, newlines: 0, rope: Rope::new() }
+    }
+
+    // Opens `file_path` synchronously, so a missing file is still reported
+    // immediately, then spawns a background thread that streams its
+    // contents over the returned channel in fixed-size byte chunks. This
+    // lets the editor start rendering and accepting navigation keys well
+    // before a large file has finished loading, instead of blocking on a
+    // BufRead::lines() pass that allocates a String per line up front.
+    fn spawn_loader<P: AsRef<Path>>(file_path: P) -> io::Result<mpsc::Receiver<io::Result<String>>> {
+        let mut f = try!(File::open(file_path));
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = vec![0; LOAD_CHUNK_SIZE];
+            // Bytes read but not yet valid UTF-8 on their own, because the
+            // chunk boundary landed inside a multi-byte character.
+            let mut carry = Vec::new();
+            loop {
+                match f.read(&mut buf) {
+                    Ok(0) => {
+                        if !carry.is_empty() {
+                            // Whatever is left at EOF isn't valid UTF-8 by
+                            // itself; repair rather than silently drop it.
+                            let _ = tx.send(Ok(String::from_utf8_lossy(&carry).into_owned()));
+                        }
+                        break;
+                    }
+                    Ok(n) => {
+                        carry.extend_from_slice(&buf[..n]);
+                        let bytes = carry;
+                        match String::from_utf8(bytes) {
+                            Ok(s) => { carry = Vec::new(); let _ = tx.send(Ok(s)); }
+                            Err(e) => {
+                                let valid_up_to = e.utf8_error().valid_up_to();
+                                let bytes = e.into_bytes();
+                                carry = bytes[valid_up_to..].to_vec();
+                                let s = String::from_utf8(bytes[..valid_up_to].to_vec()).unwrap();
+                                let _ = tx.send(Ok(s));
+                            }
+                        }
+                    }
+                    // A genuine read error (not EOF): surface it rather than
+                    // closing the channel silently, so the editor knows
+                    // `data` stopped short of the whole file instead of
+                    // mistaking the disconnect for a clean finish.
+                    Err(e) => { let _ = tx.send(Err(e)); break; }
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    // Appends `s` (the next loaded chunk, or further writes at the end of
+    // the buffer) without having to locate an insertion point first.
+    fn append(&mut self, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        self.rope.append(s);
+        self.length += s.len();
+        self.newlines += s.bytes().filter(|&b| b == b'\n').count();
+    }
+
+    fn num_lines(&self) -> usize {
+        if self.length == 0 {
+            return 0;
+        }
+        if self.rope.slice(self.length - 1, self.length) == "\n" {
+            self.newlines
+        }
+        else {
+            self.newlines + 1
+        }
     }
 
-    // NOTE: This has to be blazingly fast, but this implementation will get *very* slow for big
-    // amounts of lines.
     fn line_at(&self, p: Point) -> (usize, usize, usize) {
-        let mut line = 0;
-        let mut start = 0;
-        let mut len = 0;
-        for l in &self.text {
-            len = l.len();
-            if p > start + len {
-                start += len + 1;
-                line += 1;
+        self.rope.line_at(p)
+    }
+
+    // The next grapheme cluster boundary after `p`, within the line `p` is
+    // on. Stays put if `p` is already at (or past) the end of the line, so
+    // crossing onto the next line is the caller's job.
+    fn next_grapheme_boundary(&self, p: Point) -> Point {
+        let (_, start, len) = self.line_at(p);
+        let rel = p - start;
+        let line = self.rope.slice(start, start + len);
+        for (i, _) in line.grapheme_indices(true) {
+            if i > rel {
+                return start + i;
             }
-            else {
+        }
+        start + len
+    }
+
+    // The previous grapheme cluster boundary before `p`, within the line
+    // `p` is on. Stays put if `p` is already at the start of the line.
+    fn prev_grapheme_boundary(&self, p: Point) -> Point {
+        let (_, start, len) = self.line_at(p);
+        let rel = p - start;
+        let line = self.rope.slice(start, start + len);
+        let mut prev = 0;
+        for (i, _) in line.grapheme_indices(true) {
+            if i >= rel {
                 break;
             }
+            prev = i;
         }
-        (line, start, len)
+        start + prev
     }
 
-    fn insert(&mut self, pos: usize, s: String) {
-        unimplemented!();
+    // Terminal column width of the slice [start, end), accounting for
+    // combining marks (width 0) and East-Asian wide characters (width 2),
+    // so cursor placement and the status line's column number reflect
+    // visible position rather than byte offset.
+    fn display_width(&self, start: Point, end: Point) -> usize {
+        UnicodeWidthStr::width(self.rope.slice(start, end).as_str())
     }
 
-    fn delete(&mut self, pos: usize, count: usize) {
-        unimplemented!();
+    // Returns the (start, len) of the given 0-based line, without the
+    // trailing newline.
+    fn line_at_index(&self, line: usize) -> (usize, usize) {
+        self.rope.line_at_index(line)
     }
 
-    fn delete_line(&mut self, line: usize) {
-        if self.newlines > 0 {
-            if self.newlines == 1 {
-                self.length = 0;
-            }
-            else {
-                self.length -= self.text[line].len() + 1;
+    fn line(&self, line: usize) -> String {
+        let (start, len) = self.line_at_index(line);
+        self.rope.slice(start, start + len)
+    }
+
+    // The whole buffer as a single String, e.g. for compiling a regex
+    // search over it. O(n); only meant for occasional whole-buffer
+    // operations, not the hot path.
+    fn text(&self) -> String {
+        self.rope.to_string()
+    }
+
+    // Maps a display column within `line` to a byte offset, for
+    // translating a mouse click's screen column into a Point. Walks
+    // grapheme clusters accumulating display width rather than byte
+    // count, so wide characters and combining marks land on the right
+    // cluster; clicking past the end of the line clamps to its length.
+    fn point_at_column(&self, line: usize, col: usize) -> Point {
+        let (start, len) = self.line_at_index(line);
+        let text = self.rope.slice(start, start + len);
+        let mut width = 0;
+        for (i, g) in text.grapheme_indices(true) {
+            let w = UnicodeWidthStr::width(g);
+            if width + w > col {
+                return start + i;
             }
-            self.newlines -= 1;
-            // Index sanity should be checked by the caller. Let remove() panic if not sane.
-            self.text.remove(line);
+            width += w;
         }
+        start + len
+    }
+
+    fn insert(&mut self, pos: usize, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        self.rope.insert(pos, s);
+        self.length += s.len();
+        self.newlines += s.bytes().filter(|&b| b == b'\n').count();
+    }
+
+    // Deletes [pos, pos+count) and returns the deleted text, so callers
+    // (undo) can restore it later.
+    fn delete(&mut self, pos: usize, count: usize) -> String {
+        let count = min(count, self.length.saturating_sub(pos));
+        if count == 0 {
+            return String::new();
+        }
+        let deleted = self.rope.delete(pos, count);
+        self.length -= deleted.len();
+        self.newlines -= deleted.bytes().filter(|&b| b == b'\n').count();
+        deleted
+    }
+
+    // Deletes the given line (including its trailing newline, unless it's
+    // the last, newline-less line in the buffer) and returns the deleted
+    // text.
+    fn delete_line(&mut self, line: usize) -> String {
+        if self.num_lines() == 0 {
+            return String::new();
+        }
+        let (start, len) = self.line_at_index(line);
+        let count = if start + len < self.length { len + 1 } else { len };
+        self.delete(start, count)
     }
 }
 
 
+// Whether keys are interpreted as movement commands, as characters to
+// insert at point, as a search query being typed at the status line, or
+// as a yes/no answer to the "quit with unsaved changes?" prompt.
+enum Mode {
+    Normal,
+    Insert,
+    Search(String),
+    ConfirmQuit
+}
+
 struct Buffer {
     name: String,
     path: String,
     point: Point,
     view: View,
-    data: Text
+    data: Text,
+    mode: Mode,
+    history: History,
+    // Receives chunks from the background file loader; None once the
+    // whole file has been streamed in (or loading failed).
+    loader: Option<mpsc::Receiver<io::Result<String>>>,
+    // Set once the background loader hit a real read error partway
+    // through, as opposed to a clean EOF. Sticky, unlike `loader`, so
+    // `save` keeps refusing even after `loader` goes back to None.
+    load_failed: bool,
+    // Byte ranges of every match of the last compiled search, in order,
+    // so repeated n/N presses don't recompile the regex.
+    matches: Vec<(usize, usize)>,
+    // Index into `matches` of the match point is currently on, if any.
+    match_index: Option<usize>,
+    // Whether `data` has edits not yet written back to `path`.
+    dirty: bool,
+    // Transient feedback for the status line (e.g. a failed save or
+    // load), shown in place of the usual name/path/position line until
+    // replaced by the next message.
+    message: Option<String>
 }
 
 impl Buffer {
@@ -117,28 +307,189 @@ impl Buffer {
         let view = View {y: 0, height: (size.1 - 1) as usize};
         let name = file_path.as_ref().file_name().unwrap().to_str().unwrap().to_string();
         let path = file_path.as_ref().to_str().unwrap().to_string();
+        let loader = try!(Text::spawn_loader(&file_path));
 
         Ok(Buffer {name: name,
             path: path,
             point: 0,
             view: view,
-            data: try!(Text::from_file(file_path))
+            data: Text::empty(),
+            mode: Mode::Normal,
+            history: History::new(),
+            loader: Some(loader),
+            load_failed: false,
+            matches: Vec::new(),
+            match_index: None,
+            dirty: false,
+            message: None
         })
     }
 
+    // Writes the buffer's text back to `path`. Writes to a sibling temp
+    // file and renames it into place, so a crash or a full disk mid-write
+    // can't leave `path` half-written.
+    //
+    // Refuses to save while the background loader (chunk0-3) is still
+    // streaming the file in: `self.data` would only hold the chunks
+    // loaded so far, and writing that out would truncate the file on
+    // disk. Also refuses permanently if the loader hit a read error
+    // partway through, for the same reason.
+    fn save(&mut self) -> io::Result<()> {
+        if self.loader.is_some() {
+            return Err(io::Error::other("cannot save while file is still loading"));
+        }
+        if self.load_failed {
+            return Err(io::Error::other("cannot save: file did not finish loading"));
+        }
+        let tmp_path = format!("{}.tmp", self.path);
+        {
+            let mut f = try!(File::create(&tmp_path));
+            try!(f.write_all(self.data.text().as_bytes()));
+        }
+        try!(fs::rename(&tmp_path, &self.path));
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Pulls any chunks the background loader has ready without blocking.
+    // Returns true if new data was appended, so the caller knows to redraw.
+    fn poll_loader(&mut self) -> bool {
+        let mut appended = false;
+        let mut done = false;
+        if let Some(ref rx) = self.loader {
+            loop {
+                match rx.try_recv() {
+                    Ok(Ok(chunk)) => { self.data.append(&chunk); appended = true; }
+                    Ok(Err(e)) => {
+                        self.load_failed = true;
+                        self.message = Some(format!("error loading {}: {}", self.path, e));
+                        done = true;
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => { done = true; break; }
+                }
+            }
+        }
+        if done {
+            self.loader = None;
+        }
+        appended
+    }
+
+    // Inserts a single character at point and advances point past it.
+    fn insert_char(&mut self, c: char) {
+        let mut buf = [0; 4];
+        let s = c.encode_utf8(&mut buf);
+        let pos = self.point;
+        self.data.insert(pos, s);
+        self.point = pos + s.len();
+        self.history.record(Edit::Insert { pos: pos, text: s.to_string() });
+        self.dirty = true;
+        let (line, _, _) = self.data.line_at(self.point);
+        self.view.adjust(line);
+    }
+
+    // Deletes [pos, pos+count) and moves point to the start of the
+    // deletion. Returns the deleted text.
+    fn delete_range(&mut self, pos: usize, count: usize) -> String {
+        let deleted = self.data.delete(pos, count);
+        if !deleted.is_empty() {
+            self.history.record(Edit::Delete { pos: pos, text: deleted.clone() });
+            self.dirty = true;
+        }
+        self.point = max(0, min(self.data.length, pos));
+        let (line, _, _) = self.data.line_at(self.point);
+        self.view.adjust(line);
+        deleted
+    }
+
+    fn delete_before_point(&mut self) {
+        if self.point == 0 {
+            return;
+        }
+        let (_, start, _) = self.data.line_at(self.point);
+        let prev = if self.point == start {
+            // Already at the start of the line: join with the previous
+            // line by deleting back over its newline, same as move_left.
+            self.point - 1
+        }
+        else {
+            self.data.prev_grapheme_boundary(self.point)
+        };
+        self.delete_range(prev, self.point - prev);
+    }
+
+    // Pops an edit off the undo stack, applies its inverse, and pushes
+    // that inverse onto the redo stack.
+    fn undo(&mut self) {
+        if let Some(edit) = self.history.pop_undo() {
+            let inverse = self.apply_inverse(edit);
+            self.history.push_redo(inverse);
+        }
+    }
+
+    // Mirror image of undo(): pops from redo, applies the inverse, and
+    // pushes back onto undo.
+    fn redo(&mut self) {
+        if let Some(edit) = self.history.pop_redo() {
+            let inverse = self.apply_inverse(edit);
+            self.history.push_undo(inverse);
+        }
+    }
+
+    // Applies the inverse of `edit` directly to `data` (bypassing
+    // insert_char/delete_range so it doesn't get re-recorded into
+    // history), restores point to the edit's position, and returns the
+    // edit that would undo what was just done.
+    fn apply_inverse(&mut self, edit: Edit) -> Edit {
+        let pos = match edit { Edit::Insert { pos, .. } | Edit::Delete { pos, .. } => pos };
+        let inverse = match edit {
+            Edit::Insert { pos, text } => {
+                self.data.delete(pos, text.len());
+                Edit::Delete { pos: pos, text: text }
+            }
+            Edit::Delete { pos, text } => {
+                self.data.insert(pos, &text);
+                Edit::Insert { pos: pos, text: text }
+            }
+        };
+        self.dirty = true;
+        self.point = max(0, min(self.data.length, pos));
+        let (line, _, _) = self.data.line_at(self.point);
+        self.view.adjust(line);
+        inverse
+    }
+
     fn lines(&self) -> usize {
-        self.data.newlines
+        self.data.num_lines()
     }
 
     fn move_right(&mut self) {
-        self.point = max(0, min(self.data.length, self.point + 1));
+        let next = self.data.next_grapheme_boundary(self.point);
+        self.point = if next > self.point {
+            next
+        }
+        else {
+            // Already at the end of the line: step over its newline onto
+            // the next one.
+            min(self.data.length, self.point + 1)
+        };
         let (line, _, _) = self.data.line_at(self.point);
         self.view.adjust(line);
     }
 
     fn move_left(&mut self) {
-        if self.point > 0 {
-            self.point = max(0, self.point - 1);
+        let (_, start, _) = self.data.line_at(self.point);
+        if self.point == start {
+            // Already at the start of the line: step back over the
+            // previous line's newline.
+            if self.point > 0 {
+                self.point -= 1;
+            }
+        }
+        else {
+            self.point = self.data.prev_grapheme_boundary(self.point);
         }
         let (line, _, _) = self.data.line_at(self.point);
         self.view.adjust(line);
@@ -181,44 +532,121 @@ impl Buffer {
     }
 
     fn move_end(&mut self) {
-        let line = self.lines() - 1;
+        // lines() is 0 for a buffer with nothing loaded yet (the loader
+        // hasn't delivered its first chunk), so saturate rather than
+        // underflow.
+        let line = self.lines().saturating_sub(1);
         self.point = self.data.length;
         self.view.adjust(line);
     }
 
     fn delete_line(&mut self) {
         let (line, start, _) = self.data.line_at(self.point);
-        self.data.delete_line(line);
+        let deleted = self.data.delete_line(line);
+        if !deleted.is_empty() {
+            self.history.record(Edit::Delete { pos: start, text: deleted });
+            self.dirty = true;
+        }
         self.point = max(0, min(self.data.length, start));
         let (line, _, _) = self.data.line_at(self.point);
         self.view.adjust(line);
     }
+
+    // Compiles `pattern` and finds every match in the buffer, then jumps
+    // to the first one at or after point. An invalid pattern just clears
+    // the match list, leaving point where it was.
+    fn search(&mut self, pattern: &str) {
+        self.matches.clear();
+        self.match_index = None;
+        if let Ok(re) = Regex::new(pattern) {
+            let text = self.data.text();
+            for m in re.find_iter(&text) {
+                self.matches.push((m.start(), m.end()));
+            }
+        }
+        self.search_next();
+    }
+
+    // Jumps point to the next match after the current one, wrapping
+    // around to the first match.
+    fn search_next(&mut self) {
+        if self.matches.is_empty() {
+            return;
+        }
+        let next = match self.match_index {
+            Some(i) => (i + 1) % self.matches.len(),
+            None => self.matches.iter().position(|&(s, _)| s >= self.point).unwrap_or(0)
+        };
+        self.goto_match(next);
+    }
+
+    // Jumps point to the match before the current one, wrapping around to
+    // the last match.
+    fn search_prev(&mut self) {
+        if self.matches.is_empty() {
+            return;
+        }
+        let prev = match self.match_index {
+            Some(i) => (i + self.matches.len() - 1) % self.matches.len(),
+            None => self.matches.iter().rposition(|&(s, _)| s <= self.point).unwrap_or(self.matches.len() - 1)
+        };
+        self.goto_match(prev);
+    }
+
+    fn goto_match(&mut self, index: usize) {
+        self.match_index = Some(index);
+        self.point = self.matches[index].0;
+        let (line, _, _) = self.data.line_at(self.point);
+        self.view.adjust(line);
+    }
+
+    // Maps a mouse click at screen column `col`, row `row` (both 0-based
+    // and relative to the text area's top-left corner) to a buffer Point.
+    // The row is clamped to the last line, so clicking below the text
+    // still lands on it.
+    fn point_at(&self, col: usize, row: usize) -> Point {
+        let line = min(self.view.y + row, self.lines().saturating_sub(1));
+        self.data.point_at_column(line, col)
+    }
+
+    // Scrolls the view up one line without moving point, clamped to the
+    // top of the buffer.
+    fn scroll_up(&mut self) {
+        self.view.y = self.view.y.saturating_sub(1);
+    }
+
+    // Scrolls the view down one line without moving point, clamped so
+    // the view doesn't run past the last line.
+    fn scroll_down(&mut self) {
+        let max_y = self.lines().saturating_sub(1);
+        self.view.y = min(self.view.y + 1, max_y);
+    }
 }
 
 
-fn display(stdout: &mut io::Stdout, buffer: &Buffer) {
+fn display<W: Write>(stdout: &mut W, buffer: &Buffer) {
     display_lines(stdout, buffer);
     display_status_line(stdout, buffer);
     display_point(stdout, buffer);
     stdout.flush().unwrap();
 }
 
-fn display_lines(stdout: &mut io::Stdout, buffer: &Buffer) {
+fn display_lines<W: Write>(stdout: &mut W, buffer: &Buffer) {
     if buffer.lines() == 0 {
         write!(stdout, "{}", clear::All).unwrap();
         return;
     }
 
     let mut ln = 0;
-    let i = buffer.view.y;
-    let lines = &buffer.data.text[i..];
-    let count = lines.len();
+    let count = buffer.lines() - buffer.view.y;
 
-    for l in lines {
+    for i in buffer.view.y..buffer.lines() {
+        let (start, len) = buffer.data.line_at_index(i);
+        let line = buffer.data.line(i);
         write!(stdout, "{goto}{line}{clear}",
                // Add 1 because termion starts indexing at 1...
                goto = cursor::Goto(1, ln + 1),
-               line = l,
+               line = highlight_matches(&line, start, start + len, &buffer.matches),
                clear = clear::UntilNewline).unwrap();
         ln += 1;
         if ln as usize >= count {
@@ -232,27 +660,82 @@ fn display_lines(stdout: &mut io::Stdout, buffer: &Buffer) {
     }
 }
 
-fn display_status_line(stdout: &mut io::Stdout, buffer: &Buffer) {
+// Wraps the parts of `line` covered by any match overlapping
+// [line_start, line_end) in inverse video.
+fn highlight_matches(line: &str, line_start: usize, line_end: usize, matches: &[(usize, usize)]) -> String {
+    let spans: Vec<(usize, usize)> = matches.iter()
+        .filter_map(|&(s, e)| {
+            let s = max(s, line_start) - line_start;
+            let e = min(e, line_end).saturating_sub(line_start);
+            if s < e { Some((s, e)) } else { None }
+        })
+        .collect();
+    if spans.is_empty() {
+        return line.to_string();
+    }
+    let mut out = String::new();
+    let mut pos = 0;
+    for (s, e) in spans {
+        out.push_str(&line[pos..s]);
+        write!(out, "{}", style::Invert).unwrap();
+        out.push_str(&line[s..e]);
+        write!(out, "{}", style::Reset).unwrap();
+        pos = e;
+    }
+    out.push_str(&line[pos..]);
+    out
+}
+
+fn display_status_line<W: Write>(stdout: &mut W, buffer: &Buffer) {
     let (line, start, _) = buffer.data.line_at(buffer.point);
     write!(stdout, "{goto}", goto = cursor::Goto(1, (buffer.view.height + 1) as u16)).unwrap();
-    write!(stdout, "{bold}{color}{name} [{path}]  {column}:{line}/{lines}{boldreset}{colorreset}",
-           bold = style::Bold,
-           color = color::Fg(color::Blue),
-           name = buffer.name,
-           path = buffer.path,
-           column = buffer.point - start + 1,
-           line = line + 1,
-           lines = buffer.lines(),
-           boldreset = style::Reset,
-           colorreset = color::Fg(color::Reset)).unwrap();
+    if let Mode::Search(ref query) = buffer.mode {
+        write!(stdout, "{bold}/{query}{boldreset}",
+               bold = style::Bold,
+               query = query,
+               boldreset = style::Reset).unwrap();
+    } else if let Mode::ConfirmQuit = buffer.mode {
+        write!(stdout, "{bold}{color}Buffer modified, quit without saving? (y/n){boldreset}{colorreset}",
+               bold = style::Bold,
+               color = color::Fg(color::Red),
+               boldreset = style::Reset,
+               colorreset = color::Fg(color::Reset)).unwrap();
+    } else if let Some(ref message) = buffer.message {
+        write!(stdout, "{bold}{color}{message}{boldreset}{colorreset}",
+               bold = style::Bold,
+               color = color::Fg(color::Red),
+               message = message,
+               boldreset = style::Reset,
+               colorreset = color::Fg(color::Reset)).unwrap();
+    } else {
+        write!(stdout, "{bold}{color}{name}{dirty} [{path}]  {column}:{line}/{lines}{boldreset}{colorreset}",
+               bold = style::Bold,
+               color = color::Fg(color::Blue),
+               name = buffer.name,
+               dirty = if buffer.dirty { "*" } else { "" },
+               path = buffer.path,
+               column = buffer.data.display_width(start, buffer.point) + 1,
+               line = line + 1,
+               lines = buffer.lines(),
+               boldreset = style::Reset,
+               colorreset = color::Fg(color::Reset)).unwrap();
+    }
     write!(stdout, "{clear}", clear = clear::UntilNewline).unwrap();
 }
 
-fn display_point(stdout: &mut io::Stdout, buffer: &Buffer) {
+fn display_point<W: Write>(stdout: &mut W, buffer: &Buffer) {
+    if let Mode::Search(ref query) = buffer.mode {
+        write!(stdout, "{}",
+               // Add 1 because termion starts indexing at 1, and another 1
+               // for the leading '/'.
+               cursor::Goto((UnicodeWidthStr::width(query.as_str()) + 2) as u16,
+                            (buffer.view.height + 1) as u16)).unwrap();
+        return;
+    }
     let (line, start, _) = buffer.data.line_at(buffer.point);
     write!(stdout, "{}",
            // Add 1 because termion starts indexing at 1...
-           cursor::Goto((buffer.point - start + 1) as u16,
+           cursor::Goto((buffer.data.display_width(start, buffer.point) + 1) as u16,
                         (line - buffer.view.y + 1) as u16)).unwrap();
 }
 
@@ -264,26 +747,48 @@ macro_rules! die {
     }}
 }
 
-fn main() {
-    let mut args = env::args();
-    let file = match args.nth(1) {
-        Some(f) => { f }
-        None => { die!("Please specify a file you want to open.\n") }
-    };
-    let mut buf = match Buffer::load(&file) {
-        Ok(b) => { b }
-        Err(e) => { die!("Could not open file: '{}'.\n", e.to_string()); }
-    };
-
-    let stdin = stdin();
-    let mut stdout = stdout().into_raw_mode().unwrap();
-    let size = termion::terminal_size().unwrap();
-    print!("{}", clear::All);
-
-    display(&mut stdout, &buf);
-
-    for c in stdin.keys() {
-        match c.unwrap() {
+// Handles one key. Returns true if the editor should quit.
+fn handle_key(buf: &mut Buffer, key: Key, size: (u16, u16)) -> bool {
+    match buf.mode {
+        Mode::Insert => match key {
+            Key::Esc => { buf.mode = Mode::Normal }
+            Key::Char('\n') => { buf.insert_char('\n') }
+            Key::Char(ch) => { buf.insert_char(ch) }
+            Key::Backspace => { buf.delete_before_point() }
+            _ => { }
+        },
+        Mode::Search(_) => match key {
+            Key::Esc => {
+                buf.matches.clear();
+                buf.match_index = None;
+                buf.mode = Mode::Normal;
+            }
+            Key::Char('\n') => { buf.mode = Mode::Normal }
+            Key::Char(ch) => {
+                let mut query = match mem::replace(&mut buf.mode, Mode::Normal) {
+                    Mode::Search(q) => q,
+                    _ => unreachable!()
+                };
+                query.push(ch);
+                buf.search(&query);
+                buf.mode = Mode::Search(query);
+            }
+            Key::Backspace => {
+                let mut query = match mem::replace(&mut buf.mode, Mode::Normal) {
+                    Mode::Search(q) => q,
+                    _ => unreachable!()
+                };
+                query.pop();
+                buf.search(&query);
+                buf.mode = Mode::Search(query);
+            }
+            _ => { }
+        },
+        Mode::ConfirmQuit => match key {
+            Key::Char('y') => { return true; }
+            _ => { buf.mode = Mode::Normal; }
+        },
+        Mode::Normal => match key {
             Key::Right | Key::Char('l') => { buf.move_right() }
             Key::Left  | Key::Char('j') => { buf.move_left() }
             Key::Down  | Key::Char('k') => { buf.move_down() }
@@ -304,13 +809,119 @@ fn main() {
             Key::Char('<') => { buf.move_start() }
 
             Key::Char('d') => { buf.delete_line() }
-            Key::Char('q') => { break; }
+            Key::Char('u') => { buf.undo() }
+            Key::Char('U') => { buf.redo() }
+            Key::Char('a') => { buf.mode = Mode::Insert }
+            Key::Char('/') => { buf.mode = Mode::Search(String::new()) }
+            Key::Char('n') => { buf.search_next() }
+            Key::Char('N') => { buf.search_prev() }
+            Key::Char('s') => {
+                match buf.save() {
+                    Ok(()) => { buf.message = None; }
+                    Err(e) => { buf.message = Some(format!("save failed: {}", e)); }
+                }
+            }
+            Key::Char('q') => {
+                if buf.dirty {
+                    buf.mode = Mode::ConfirmQuit;
+                }
+                else {
+                    return true;
+                }
+            }
             _ => { }
         }
+    }
+    false
+}
 
-        display(&mut stdout, &buf);
+// Handles one input event. Returns true if the editor should quit.
+fn handle_event(buf: &mut Buffer, event: Event, size: (u16, u16)) -> bool {
+    match event {
+        Event::Key(key) => handle_key(buf, key, size),
+        Event::Mouse(mouse) => { handle_mouse(buf, mouse); false }
+        Event::Unsupported(_) => false
     }
+}
+
+// Handles a mouse click, release or scroll-wheel event. Left-click (and
+// release) moves point to the clicked position; the wheel scrolls the
+// view without disturbing point. Clicks on the status line (the row at
+// view.height) are ignored, since there's no buffer position there.
+fn handle_mouse(buf: &mut Buffer, mouse: MouseEvent) {
+    match mouse {
+        MouseEvent::Press(MouseButton::Left, col, row) | MouseEvent::Release(col, row) => {
+            // Termion reports 1-based screen coordinates.
+            let row = row as usize - 1;
+            let col = col as usize - 1;
+            if row < buf.view.height {
+                buf.point = buf.point_at(col, row);
+                let (line, _, _) = buf.data.line_at(buf.point);
+                buf.view.adjust(line);
+            }
+        }
+        MouseEvent::Press(MouseButton::WheelUp, _, _) => { buf.scroll_up() }
+        MouseEvent::Press(MouseButton::WheelDown, _, _) => { buf.scroll_down() }
+        _ => { }
+    }
+}
+
+fn main() {
+    let mut args = env::args();
+    let file = match args.nth(1) {
+        Some(f) => { f }
+        None => { die!("Please specify a file you want to open.\n") }
+    };
+    let mut buf = match Buffer::load(&file) {
+        Ok(b) => { b }
+        Err(e) => { die!("Could not open file: '{}'.\n", e.to_string()); }
+    };
 
-    // Reset the cursor to the bottom.
-    println!("{}", cursor::Goto(1, size.1 + 1));
+    // The default panic hook prints straight to stderr, which is fine, but
+    // if we're still on the alternate screen the message renders into a
+    // buffer that's about to be thrown away instead of the user's
+    // scrollback. Switch back to the main screen (and show the cursor,
+    // which raw mode hides) before handing off to it; restoring raw mode
+    // itself is still the raw-mode guard's job below, which runs as the
+    // panic unwinds.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        print!("{}{}", ToMainScreen, cursor::Show);
+        let _ = io::stdout().flush();
+        default_hook(info);
+    }));
+
+    // Non-blocking so the loop below can also poll the background file
+    // loader instead of sitting blocked on a key press. events() (rather
+    // than keys()) so clicks and scroll-wheel motion come through too.
+    let mut events = termion::async_stdin().events();
+    // Dropped in the reverse order it's composed: mouse reporting and the
+    // alternate screen are torn down (restoring the user's original shell
+    // contents) before raw mode is disabled, whether we get here by
+    // breaking out of the loop below or by unwinding from a panic.
+    let mut stdout = MouseTerminal::from(AlternateScreen::from(stdout().into_raw_mode().unwrap()));
+    let size = termion::terminal_size().unwrap();
+    print!("{}", clear::All);
+
+    display(&mut stdout, &buf);
+
+    loop {
+        let mut redraw = buf.poll_loader();
+
+        if let Some(e) = events.next() {
+            if handle_event(&mut buf, e.unwrap(), size) {
+                break;
+            }
+            redraw = true;
+        }
+        else {
+            // Nothing waiting right now: avoid busy-looping the CPU while
+            // polling for the next key or the next loaded chunk.
+            thread::sleep(Duration::from_millis(16));
+        }
+
+        if redraw {
+            display(&mut stdout, &buf);
+        }
+    }
 }
@@ -0,0 +1,479 @@
+// A simple rope: a binary tree of string leaves. Each internal node caches
+// the byte length and newline count of its left subtree, so indexing by a
+// byte offset, or by line number, only has to compare against the cached
+// weights on the way down instead of touching every leaf.
+//
+// Known gap: `insert`/`delete` walk down and mutate a single path in
+// place and never rebalance, so a long-running editing session with many
+// scattered edits will grow the tree deeper and deeper, degrading lookups
+// towards O(depth) instead of the O(log n) this structure is meant to
+// provide. Only the bulk `build_balanced` path used by `from_str`/
+// `append` produces a balanced tree. Good enough for now; rebalancing
+// insert/delete can be bolted on later if it ever matters in practice.
+
+use std::cmp::min;
+
+// Leaves longer than this get split in two on insert, so the leaf string
+// that each edit rebuilds stays small.
+const MAX_LEAF: usize = 1024;
+
+enum Node {
+    Leaf(String),
+    Internal {
+        left: Box<Node>,
+        right: Box<Node>,
+        weight: usize,       // byte length of the left subtree
+        left_newlines: usize // newline count of the left subtree
+    }
+}
+
+impl Node {
+    fn len(&self) -> usize {
+        match *self {
+            Node::Leaf(ref s) => s.len(),
+            Node::Internal { weight, ref right, .. } => weight + right.len()
+        }
+    }
+
+    fn newlines(&self) -> usize {
+        match *self {
+            Node::Leaf(ref s) => s.bytes().filter(|&b| b == b'\n').count(),
+            Node::Internal { left_newlines, ref right, .. } => left_newlines + right.newlines()
+        }
+    }
+
+    fn concat(left: Node, right: Node) -> Node {
+        let weight = left.len();
+        let left_newlines = left.newlines();
+        Node::Internal { left: Box::new(left), right: Box::new(right), weight: weight, left_newlines: left_newlines }
+    }
+
+    fn insert(self, pos: usize, s: &str) -> Node {
+        match self {
+            Node::Leaf(mut text) => {
+                let tail = text.split_off(pos);
+                text.push_str(s);
+                text.push_str(&tail);
+                if text.len() > MAX_LEAF {
+                    let mid = floor_char_boundary(&text, text.len() / 2);
+                    let right = text.split_off(mid);
+                    Node::concat(Node::Leaf(text), Node::Leaf(right))
+                }
+                else {
+                    Node::Leaf(text)
+                }
+            }
+            Node::Internal { left, right, weight, .. } => {
+                if pos <= weight {
+                    Node::concat((*left).insert(pos, s), *right)
+                }
+                else {
+                    Node::concat(*left, (*right).insert(pos - weight, s))
+                }
+            }
+        }
+    }
+
+    // Deletes the byte range [pos, pos+count), appends the deleted text to
+    // `out`, and returns the replacement node (None if nothing is left).
+    fn delete(self, pos: usize, count: usize, out: &mut String) -> Option<Node> {
+        if count == 0 {
+            return Some(self);
+        }
+        match self {
+            Node::Leaf(mut text) => {
+                let end = min(pos + count, text.len());
+                out.push_str(&text[pos..end]);
+                text.replace_range(pos..end, "");
+                if text.is_empty() { None } else { Some(Node::Leaf(text)) }
+            }
+            Node::Internal { left, right, weight, .. } => {
+                let left_count = if pos < weight { min(count, weight - pos) } else { 0 };
+                let new_left = if left_count > 0 {
+                    (*left).delete(pos, left_count, out)
+                }
+                else {
+                    Some(*left)
+                };
+                let right_pos = if pos > weight { pos - weight } else { 0 };
+                let right_count = count - left_count;
+                let new_right = if right_count > 0 {
+                    (*right).delete(right_pos, right_count, out)
+                }
+                else {
+                    Some(*right)
+                };
+                match (new_left, new_right) {
+                    (Some(l), Some(r)) => Some(Node::concat(l, r)),
+                    (Some(l), None) => Some(l),
+                    (None, Some(r)) => Some(r),
+                    (None, None) => None
+                }
+            }
+        }
+    }
+
+    fn slice_into(&self, base: usize, start: usize, end: usize, out: &mut String) {
+        if start >= end {
+            return;
+        }
+        match *self {
+            Node::Leaf(ref s) => {
+                let local_start = start.saturating_sub(base);
+                let local_end = min(end.saturating_sub(base), s.len());
+                if local_start < local_end {
+                    out.push_str(&s[local_start..local_end]);
+                }
+            }
+            Node::Internal { ref left, ref right, weight, .. } => {
+                let left_end = base + weight;
+                if start < left_end {
+                    left.slice_into(base, start, min(end, left_end), out);
+                }
+                if end > left_end {
+                    right.slice_into(left_end, start, end, out);
+                }
+            }
+        }
+    }
+
+    // Last newline at an absolute index strictly less than `upto`.
+    fn rfind_newline(&self, base: usize, upto: usize) -> Option<usize> {
+        if upto <= base {
+            return None;
+        }
+        match *self {
+            Node::Leaf(ref s) => {
+                let local_upto = min(upto - base, s.len());
+                s[..local_upto].rfind('\n').map(|i| base + i)
+            }
+            Node::Internal { ref left, ref right, weight, .. } => {
+                let left_end = base + weight;
+                if upto > left_end {
+                    if let Some(p) = right.rfind_newline(left_end, upto) {
+                        return Some(p);
+                    }
+                }
+                left.rfind_newline(base, min(upto, left_end))
+            }
+        }
+    }
+
+    // First newline at an absolute index greater than or equal to `from`.
+    fn find_newline(&self, base: usize, from: usize) -> Option<usize> {
+        match *self {
+            Node::Leaf(ref s) => {
+                if base + s.len() <= from {
+                    return None;
+                }
+                let local_from = from.saturating_sub(base);
+                s[local_from..].find('\n').map(|i| base + local_from + i)
+            }
+            Node::Internal { ref left, ref right, weight, .. } => {
+                let left_end = base + weight;
+                if from < left_end {
+                    if let Some(p) = left.find_newline(base, from) {
+                        return Some(p);
+                    }
+                }
+                right.find_newline(left_end, if from > left_end { from } else { left_end })
+            }
+        }
+    }
+
+    // Number of newlines at an absolute index strictly less than `upto`.
+    fn newlines_before(&self, base: usize, upto: usize) -> usize {
+        match *self {
+            Node::Leaf(ref s) => {
+                let local_upto = if upto > base { min(upto - base, s.len()) } else { 0 };
+                s[..local_upto].bytes().filter(|&b| b == b'\n').count()
+            }
+            Node::Internal { ref left, ref right, weight, left_newlines } => {
+                let left_end = base + weight;
+                if upto <= left_end {
+                    left.newlines_before(base, upto)
+                }
+                else {
+                    left_newlines + right.newlines_before(left_end, upto)
+                }
+            }
+        }
+    }
+
+    // Byte offset where the given (0-based) line starts.
+    fn line_start(&self, base: usize, line: usize) -> usize {
+        match *self {
+            Node::Leaf(ref s) => {
+                if line == 0 {
+                    return base;
+                }
+                let mut count = 0;
+                for (i, b) in s.bytes().enumerate() {
+                    if b == b'\n' {
+                        count += 1;
+                        if count == line {
+                            return base + i + 1;
+                        }
+                    }
+                }
+                base + s.len()
+            }
+            Node::Internal { ref left, ref right, weight, left_newlines } => {
+                if line <= left_newlines {
+                    left.line_start(base, line)
+                }
+                else {
+                    right.line_start(base + weight, line - left_newlines)
+                }
+            }
+        }
+    }
+}
+
+fn floor_char_boundary(s: &str, mut i: usize) -> usize {
+    while i > 0 && !s.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+pub struct Rope {
+    root: Option<Node>
+}
+
+impl Rope {
+    pub fn new() -> Rope {
+        Rope { root: None }
+    }
+
+    pub fn from_str(s: &str) -> Rope {
+        if s.is_empty() {
+            return Rope::new();
+        }
+        let mut leaves: Vec<Node> = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let split = min(MAX_LEAF, rest.len());
+            let split = floor_char_boundary(rest, split);
+            let (chunk, tail) = rest.split_at(if split == 0 { rest.len() } else { split });
+            leaves.push(Node::Leaf(chunk.to_string()));
+            rest = tail;
+        }
+        Rope { root: Some(build_balanced(leaves)) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.as_ref().map_or(0, |n| n.len())
+    }
+
+    // Appends `s` to the end of the rope, building it as its own balanced
+    // subtree first instead of going through `insert`'s single-leaf
+    // split. That matters for chunked file loading: appending a multi-MiB
+    // chunk through `insert` would grow one oversized leaf and split it
+    // once, while this produces properly sized leaves all the way through.
+    pub fn append(&mut self, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        let tail = Rope::from_str(s).root;
+        let root = self.root.take();
+        self.root = match (root, tail) {
+            (Some(a), Some(b)) => Some(Node::concat(a, b)),
+            (Some(a), None) => Some(a),
+            (None, b) => b
+        };
+    }
+
+    pub fn insert(&mut self, pos: usize, s: &str) {
+        if s.is_empty() {
+            return;
+        }
+        let root = self.root.take();
+        self.root = Some(match root {
+            Some(n) => n.insert(pos, s),
+            None => Node::Leaf(s.to_string())
+        });
+    }
+
+    // Deletes [pos, pos+count) and returns the deleted text.
+    pub fn delete(&mut self, pos: usize, count: usize) -> String {
+        let mut out = String::new();
+        if count == 0 {
+            return out;
+        }
+        let root = self.root.take();
+        self.root = root.and_then(|n| n.delete(pos, count, &mut out));
+        out
+    }
+
+    pub fn slice(&self, start: usize, end: usize) -> String {
+        let mut out = String::new();
+        if let Some(ref n) = self.root {
+            n.slice_into(0, start, end, &mut out);
+        }
+        out
+    }
+
+    // Returns (line, line_start, line_len) for the line containing `p`.
+    pub fn line_at(&self, p: usize) -> (usize, usize, usize) {
+        let n = match self.root {
+            Some(ref n) => n,
+            None => return (0, 0, 0)
+        };
+        let start = match n.rfind_newline(0, p) {
+            Some(i) => i + 1,
+            None => 0
+        };
+        let end = match n.find_newline(0, start) {
+            Some(i) => i,
+            None => self.len()
+        };
+        let line = n.newlines_before(0, start);
+        (line, start, end - start)
+    }
+
+    // Returns (line_start, line_len) for the given 0-based line number.
+    pub fn line_at_index(&self, line: usize) -> (usize, usize) {
+        let n = match self.root {
+            Some(ref n) => n,
+            None => return (0, 0)
+        };
+        let start = n.line_start(0, line);
+        let end = match n.find_newline(0, start) {
+            Some(i) => i,
+            None => self.len()
+        };
+        (start, end - start)
+    }
+
+    pub fn to_string(&self) -> String {
+        self.slice(0, self.len())
+    }
+}
+
+fn build_balanced(mut leaves: Vec<Node>) -> Node {
+    // Repeatedly fold neighbouring pairs until a single node remains, which
+    // keeps the tree roughly log(n) deep for the initial load instead of a
+    // long right-leaning chain.
+    if leaves.is_empty() {
+        return Node::Leaf(String::new());
+    }
+    while leaves.len() > 1 {
+        let mut next = Vec::with_capacity((leaves.len() + 1) / 2);
+        let mut it = leaves.into_iter();
+        loop {
+            match (it.next(), it.next()) {
+                (Some(a), Some(b)) => next.push(Node::concat(a, b)),
+                (Some(a), None) => { next.push(a); break; }
+                (None, _) => break
+            }
+        }
+        leaves = next;
+    }
+    leaves.pop().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_into_empty() {
+        let mut r = Rope::new();
+        r.insert(0, "hello");
+        assert_eq!(r.to_string(), "hello");
+    }
+
+    #[test]
+    fn insert_multibyte_mid_string() {
+        let mut r = Rope::from_str("ab");
+        r.insert(1, "日本語");
+        assert_eq!(r.to_string(), "a日本語b");
+    }
+
+    #[test]
+    fn insert_splits_oversized_leaf() {
+        let mut r = Rope::from_str(&"x".repeat(MAX_LEAF));
+        r.insert(MAX_LEAF, "y");
+        assert_eq!(r.len(), MAX_LEAF + 1);
+        assert_eq!(r.to_string(), format!("{}y", "x".repeat(MAX_LEAF)));
+    }
+
+    #[test]
+    fn delete_range_returns_deleted_text() {
+        let mut r = Rope::from_str("hello world");
+        let deleted = r.delete(5, 6);
+        assert_eq!(deleted, " world");
+        assert_eq!(r.to_string(), "hello");
+    }
+
+    #[test]
+    fn delete_whole_multibyte_char() {
+        let mut r = Rope::from_str("a日b");
+        let deleted = r.delete(1, "日".len());
+        assert_eq!(deleted, "日");
+        assert_eq!(r.to_string(), "ab");
+    }
+
+    #[test]
+    fn delete_everything_leaves_empty_rope() {
+        let mut r = Rope::from_str("hi");
+        r.delete(0, 2);
+        assert_eq!(r.len(), 0);
+        assert_eq!(r.to_string(), "");
+    }
+
+    #[test]
+    fn delete_across_leaf_boundary() {
+        let mut r = Rope::from_str(&"a".repeat(MAX_LEAF + 10));
+        let deleted = r.delete(MAX_LEAF - 2, 5);
+        assert_eq!(deleted.len(), 5);
+        assert_eq!(r.len(), MAX_LEAF + 5);
+    }
+
+    #[test]
+    fn slice_returns_substring() {
+        let r = Rope::from_str("hello world");
+        assert_eq!(r.slice(6, 11), "world");
+    }
+
+    #[test]
+    fn line_at_first_line() {
+        let r = Rope::from_str("one\ntwo\nthree");
+        let (line, start, len) = r.line_at(1);
+        assert_eq!((line, start, len), (0, 0, 3));
+    }
+
+    #[test]
+    fn line_at_middle_line() {
+        let r = Rope::from_str("one\ntwo\nthree");
+        let (line, start, len) = r.line_at(5);
+        assert_eq!((line, start, len), (1, 4, 3));
+    }
+
+    #[test]
+    fn line_at_last_line_no_trailing_newline() {
+        let r = Rope::from_str("one\ntwo\nthree");
+        let (line, start, len) = r.line_at(10);
+        assert_eq!((line, start, len), (2, 8, 5));
+    }
+
+    #[test]
+    fn line_at_exactly_on_newline_boundary() {
+        let r = Rope::from_str("one\ntwo");
+        // Byte 3 is the '\n' itself, still part of line 0.
+        let (line, start, len) = r.line_at(3);
+        assert_eq!((line, start, len), (0, 0, 3));
+        // Byte 4 is just after the '\n', the start of line 1.
+        let (line, start, len) = r.line_at(4);
+        assert_eq!((line, start, len), (1, 4, 3));
+    }
+
+    #[test]
+    fn line_at_index_by_line_number() {
+        let r = Rope::from_str("one\ntwo\nthree");
+        assert_eq!(r.line_at_index(0), (0, 3));
+        assert_eq!(r.line_at_index(1), (4, 3));
+        assert_eq!(r.line_at_index(2), (8, 5));
+    }
+}
@@ -0,0 +1,146 @@
+// An edit journal for Buffer: every mutation is recorded as a reversible
+// operation so it can be undone, and the inverse of an undo is pushed onto
+// the redo stack so redo is just "undo the undo".
+
+pub enum Edit {
+    Insert { pos: usize, text: String },
+    Delete { pos: usize, text: String }
+}
+
+pub struct History {
+    undo: Vec<Edit>,
+    redo: Vec<Edit>
+}
+
+impl History {
+    pub fn new() -> History {
+        History { undo: Vec::new(), redo: Vec::new() }
+    }
+
+    // Records a new edit, clearing the redo stack (it no longer applies
+    // once the buffer has diverged) and coalescing consecutive
+    // single-character inserts so typing a word undoes as one step.
+    pub fn record(&mut self, edit: Edit) {
+        self.redo.clear();
+        if let Edit::Insert { pos, ref text } = edit {
+            if text.chars().count() == 1 {
+                let coalesced = match self.undo.last_mut() {
+                    Some(&mut Edit::Insert { pos: last_pos, text: ref mut last_text }) =>
+                        if last_pos + last_text.len() == pos {
+                            last_text.push_str(text);
+                            true
+                        }
+                        else {
+                            false
+                        },
+                    _ => false
+                };
+                if coalesced {
+                    return;
+                }
+            }
+        }
+        self.undo.push(edit);
+    }
+
+    pub fn pop_undo(&mut self) -> Option<Edit> {
+        self.undo.pop()
+    }
+
+    pub fn push_undo(&mut self, edit: Edit) {
+        self.undo.push(edit);
+    }
+
+    pub fn pop_redo(&mut self) -> Option<Edit> {
+        self.redo.pop()
+    }
+
+    pub fn push_redo(&mut self, edit: Edit) {
+        self.redo.push(edit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_then_pop_undo() {
+        let mut h = History::new();
+        h.record(Edit::Insert { pos: 0, text: "hi".to_string() });
+        match h.pop_undo() {
+            Some(Edit::Insert { pos, text }) => {
+                assert_eq!(pos, 0);
+                assert_eq!(text, "hi");
+            }
+            _ => panic!("expected an Insert edit")
+        }
+        assert!(h.pop_undo().is_none());
+    }
+
+    #[test]
+    fn coalesces_consecutive_single_char_inserts() {
+        let mut h = History::new();
+        h.record(Edit::Insert { pos: 0, text: "a".to_string() });
+        h.record(Edit::Insert { pos: 1, text: "b".to_string() });
+        h.record(Edit::Insert { pos: 2, text: "c".to_string() });
+        match h.pop_undo() {
+            Some(Edit::Insert { pos, text }) => {
+                assert_eq!(pos, 0);
+                assert_eq!(text, "abc");
+            }
+            _ => panic!("expected a single coalesced Insert edit")
+        }
+        assert!(h.pop_undo().is_none());
+    }
+
+    #[test]
+    fn does_not_coalesce_non_adjacent_inserts() {
+        let mut h = History::new();
+        h.record(Edit::Insert { pos: 0, text: "a".to_string() });
+        h.record(Edit::Insert { pos: 5, text: "b".to_string() });
+        assert!(h.pop_undo().is_some());
+        assert!(h.pop_undo().is_some());
+        assert!(h.pop_undo().is_none());
+    }
+
+    #[test]
+    fn does_not_coalesce_multi_char_insert() {
+        // Only the incoming edit being a single character is coalesced;
+        // a multi-char insert (e.g. a paste) always gets its own entry.
+        let mut h = History::new();
+        h.record(Edit::Insert { pos: 0, text: "a".to_string() });
+        h.record(Edit::Insert { pos: 1, text: "bc".to_string() });
+        assert!(h.pop_undo().is_some());
+        assert!(h.pop_undo().is_some());
+        assert!(h.pop_undo().is_none());
+    }
+
+    #[test]
+    fn record_clears_redo_stack() {
+        let mut h = History::new();
+        h.push_redo(Edit::Delete { pos: 0, text: "x".to_string() });
+        h.record(Edit::Insert { pos: 0, text: "ab".to_string() });
+        assert!(h.pop_redo().is_none());
+    }
+
+    #[test]
+    fn undo_redo_round_trip() {
+        let mut h = History::new();
+        h.record(Edit::Insert { pos: 0, text: "ab".to_string() });
+        let edit = h.pop_undo().unwrap();
+        // Simulate Buffer::undo: apply the inverse, push it to redo.
+        let inverse = match edit {
+            Edit::Insert { pos, text } => Edit::Delete { pos: pos, text: text },
+            Edit::Delete { pos, text } => Edit::Insert { pos: pos, text: text }
+        };
+        h.push_redo(inverse);
+        match h.pop_redo() {
+            Some(Edit::Delete { pos, text }) => {
+                assert_eq!(pos, 0);
+                assert_eq!(text, "ab");
+            }
+            _ => panic!("expected the inverse Delete edit")
+        }
+    }
+}